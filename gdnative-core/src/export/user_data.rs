@@ -0,0 +1,524 @@
+//! Types and traits for wrapping the Rust payload ("user data") of a `NativeClass` instance,
+//! and for synchronizing access to it across calls coming from Godot.
+//!
+//! A `NativeClass` instance's user data is shared: the same wrapper is reachable from every
+//! variant call that targets the instance, including calls that originate *during* another
+//! call to the same instance (e.g. a method that calls back into itself through a signal, or
+//! a Rust-hosted interpreter that re-enters the object it is currently running). The wrapper
+//! types in this module decide what happens in that situation.
+
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::fmt;
+use std::ops::{Deref, DerefMut};
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+
+use crate::core_types::{GodotString, ToVariant, Variant};
+
+/// Trait for the wrapper type used to store the payload of a `NativeClass` instance.
+///
+/// See the module-level documentation for more information.
+pub trait UserData: Sized + Clone + 'static {
+    /// The wrapped type.
+    type Target;
+
+    /// Creates a new wrapper from an initial value.
+    fn new(val: Self::Target) -> Self;
+
+    /// Calls a closure with an immutable reference to the wrapped value.
+    ///
+    /// Implementations should allow concurrent immutable access from re-entrant calls to the
+    /// same instance: only mutable access should conflict with another borrow. [`MutexData`]
+    /// is the one exception, since the underlying `Mutex` has no separate shared-lock mode --
+    /// see its docs for details.
+    fn map<F, U>(&self, op: F) -> Result<U, ReentrantCallError>
+    where
+        F: FnOnce(&Self::Target) -> U;
+
+    /// Calls a closure with a mutable reference to the wrapped value.
+    ///
+    /// If this call is re-entrant -- i.e. it happens while a mutable borrow for the *same*
+    /// instance is already live further up the current thread's call stack -- this returns
+    /// `Err(ReentrantCallError)` instead of panicking or deadlocking. Calls for unrelated
+    /// instances are never affected by each other.
+    fn map_mut<F, U>(&self, op: F) -> Result<U, ReentrantCallError>
+    where
+        F: FnOnce(&mut Self::Target) -> U;
+}
+
+/// Error returned when a dispatch would have to re-enter a `UserData` wrapper that is already
+/// mutably borrowed further up the same thread's call stack.
+///
+/// This is the typed error produced instead of the panic/abort that would otherwise result
+/// from a recursive or cyclic variant call into the same `NativeClass` instance. It converts
+/// to a Godot-facing `Variant` so that exported methods can propagate it as an ordinary
+/// script-level error rather than crashing the engine.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReentrantCallError {
+    instance_ptr: usize,
+}
+
+impl ReentrantCallError {
+    fn new(instance_ptr: usize) -> Self {
+        ReentrantCallError { instance_ptr }
+    }
+}
+
+impl fmt::Display for ReentrantCallError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "instance at {:#x} is already mutably borrowed by an enclosing call on this thread",
+            self.instance_ptr
+        )
+    }
+}
+
+impl std::error::Error for ReentrantCallError {}
+
+impl ToVariant for ReentrantCallError {
+    fn to_variant(&self) -> Variant {
+        GodotString::from(self.to_string()).to_variant()
+    }
+}
+
+thread_local! {
+    /// Set of instances that currently have a live mutable (or, for `MutexData`, any) borrow
+    /// somewhere on *this* thread's call stack, keyed by the address of the allocation backing
+    /// a `UserData` wrapper. Presence of a key means some enclosing frame on this thread
+    /// already holds that borrow.
+    ///
+    /// The set only ever holds entries for instances currently being dispatched into, so it
+    /// stays small; entries are removed again as soon as the enclosing `ReentrancyGuard` drops.
+    static ACTIVE_MUT_BORROWS: RefCell<HashSet<usize>> = RefCell::new(HashSet::new());
+}
+
+/// RAII guard that records a re-entrant dispatch into the instance identified by
+/// `instance_ptr` for as long as it is alive. The record is removed again on drop, including
+/// on unwind, so a panicking method body can never leave the set poisoned for subsequent calls.
+struct ReentrancyGuard {
+    instance_ptr: usize,
+}
+
+impl ReentrancyGuard {
+    /// Attempts to enter a dispatch for `instance_ptr`. Fails if this thread is already inside
+    /// a dispatch for the same instance.
+    fn enter(instance_ptr: usize) -> Result<Self, ReentrantCallError> {
+        let inserted =
+            ACTIVE_MUT_BORROWS.with(|borrows| borrows.borrow_mut().insert(instance_ptr));
+
+        if !inserted {
+            return Err(ReentrantCallError::new(instance_ptr));
+        }
+
+        Ok(ReentrancyGuard { instance_ptr })
+    }
+}
+
+impl Drop for ReentrancyGuard {
+    fn drop(&mut self) {
+        ACTIVE_MUT_BORROWS.with(|borrows| {
+            borrows.borrow_mut().remove(&self.instance_ptr);
+        });
+    }
+}
+
+/// `UserData` wrapper that uses a `Mutex` to synchronize access, for `NativeClass` types that
+/// are `Send` but not necessarily safe for concurrent `&self` access.
+///
+/// Unlike [`LocalCellData`] and [`ReentrantCell`], `MutexData` cannot allow concurrent
+/// immutable re-entrant access: `std::sync::Mutex` has no separate shared-lock mode, so two
+/// nested `map` calls for the same instance would contend for the same exclusive lock. Both
+/// `map` and `map_mut` therefore go through the same re-entrancy check, and *any* re-entrant
+/// call -- immutable or mutable -- returns `Err(ReentrantCallError)` rather than deadlocking.
+pub struct MutexData<T> {
+    lock: Arc<Mutex<T>>,
+}
+
+// Implemented manually rather than derived: `#[derive(Clone)]` would incorrectly require
+// `T: Clone`, even though cloning only clones the `Arc`, not the wrapped value.
+impl<T> Clone for MutexData<T> {
+    fn clone(&self) -> Self {
+        MutexData {
+            lock: self.lock.clone(),
+        }
+    }
+}
+
+impl<T> UserData for MutexData<T>
+where
+    T: Send + 'static,
+{
+    type Target = T;
+
+    fn new(val: Self::Target) -> Self {
+        MutexData {
+            lock: Arc::new(Mutex::new(val)),
+        }
+    }
+
+    fn map<F, U>(&self, op: F) -> Result<U, ReentrantCallError>
+    where
+        F: FnOnce(&Self::Target) -> U,
+    {
+        let instance_ptr = Arc::as_ptr(&self.lock) as usize;
+        let _reentrancy = ReentrancyGuard::enter(instance_ptr)?;
+
+        let guard = self.lock.lock().unwrap_or_else(|p| p.into_inner());
+        Ok(op(&guard))
+    }
+
+    fn map_mut<F, U>(&self, op: F) -> Result<U, ReentrantCallError>
+    where
+        F: FnOnce(&mut Self::Target) -> U,
+    {
+        let instance_ptr = Arc::as_ptr(&self.lock) as usize;
+        let _reentrancy = ReentrancyGuard::enter(instance_ptr)?;
+
+        let mut guard = self.lock.lock().unwrap_or_else(|p| p.into_inner());
+        Ok(op(&mut guard))
+    }
+}
+
+/// `UserData` wrapper backed by a `RefCell`, for `NativeClass` types that are only ever
+/// accessed from the single thread that owns the Godot main loop.
+pub struct LocalCellData<T> {
+    cell: Rc<RefCell<T>>,
+}
+
+// Implemented manually rather than derived: `#[derive(Clone)]` would incorrectly require
+// `T: Clone`, even though cloning only clones the `Rc`, not the wrapped value.
+impl<T> Clone for LocalCellData<T> {
+    fn clone(&self) -> Self {
+        LocalCellData {
+            cell: self.cell.clone(),
+        }
+    }
+}
+
+// SAFETY: instances are only ever dispatched into from the thread that holds Godot's main
+// loop, so a `LocalCellData` is never actually shared across threads despite the `Rc`/
+// `RefCell` it is built on.
+unsafe impl<T> Send for LocalCellData<T> {}
+unsafe impl<T> Sync for LocalCellData<T> {}
+
+impl<T> UserData for LocalCellData<T>
+where
+    T: 'static,
+{
+    type Target = T;
+
+    fn new(val: Self::Target) -> Self {
+        LocalCellData {
+            cell: Rc::new(RefCell::new(val)),
+        }
+    }
+
+    fn map<F, U>(&self, op: F) -> Result<U, ReentrantCallError>
+    where
+        F: FnOnce(&Self::Target) -> U,
+    {
+        let instance_ptr = Rc::as_ptr(&self.cell) as usize;
+        match self.cell.try_borrow() {
+            Ok(val) => Ok(op(&val)),
+            // Only an enclosing *mutable* borrow can make this fail; surface it the same way
+            // as the mutable path below, rather than panicking.
+            Err(_) => Err(ReentrantCallError::new(instance_ptr)),
+        }
+    }
+
+    fn map_mut<F, U>(&self, op: F) -> Result<U, ReentrantCallError>
+    where
+        F: FnOnce(&mut Self::Target) -> U,
+    {
+        let instance_ptr = Rc::as_ptr(&self.cell) as usize;
+        let _reentrancy = ReentrancyGuard::enter(instance_ptr)?;
+
+        let mut val = self
+            .cell
+            .try_borrow_mut()
+            .map_err(|_| ReentrantCallError::new(instance_ptr))?;
+        Ok(op(&mut val))
+    }
+}
+
+/// `UserData` wrapper for scripting-language backends that need genuine recursion into the
+/// same instance, such as a Rust-hosted interpreter whose `call` implementation must itself
+/// invoke `call` on the instance it is currently running on.
+///
+/// Unlike the other wrappers in this module, `ReentrantCell` does not merely detect
+/// re-entrancy and fail -- it lets a method body voluntarily give the value back up for the
+/// span of a sub-call via [`ReentrantGuard::with_released`], so the sub-call can legitimately
+/// borrow the same instance again. Outside of such a released span, a nested access still
+/// observes an explicit "value in use" state (this wrapper's flavor of
+/// [`ReentrantCallError`]) rather than a panic.
+///
+/// This is a single-threaded, `Rc`-backed wrapper: like [`LocalCellData`], it assumes
+/// instances are only ever dispatched into from the thread that owns Godot's main loop.
+pub struct ReentrantCell<T> {
+    // `None` exactly while the value has been handed out by `borrow_mut`. A released span
+    // opened by `ReentrantGuard::with_released` puts the value back here for its duration.
+    slot: Rc<RefCell<Option<T>>>,
+}
+
+// Implemented manually rather than derived: `#[derive(Clone)]` would incorrectly require
+// `T: Clone`, even though cloning only clones the `Rc`, not the wrapped value.
+impl<T> Clone for ReentrantCell<T> {
+    fn clone(&self) -> Self {
+        ReentrantCell {
+            slot: self.slot.clone(),
+        }
+    }
+}
+
+// SAFETY: see `LocalCellData`; the same single-threaded dispatch guarantee applies here.
+unsafe impl<T> Send for ReentrantCell<T> {}
+unsafe impl<T> Sync for ReentrantCell<T> {}
+
+impl<T> ReentrantCell<T> {
+    /// Borrows the inner value mutably, returning a guard that derefs to `&mut T` and can
+    /// additionally release the borrow for the span of a re-entrant sub-call.
+    ///
+    /// Fails with [`ReentrantCallError`] if the value is already taken, i.e. an enclosing
+    /// `borrow_mut` on this thread has not released it.
+    pub fn borrow_mut(&self) -> Result<ReentrantGuard<'_, T>, ReentrantCallError> {
+        let instance_ptr = Rc::as_ptr(&self.slot) as usize;
+        // `try_borrow_mut`, not `borrow_mut`: a `map` call further up the stack may be holding
+        // a shared `Ref` into this same `RefCell` for the duration of its closure, and taking
+        // a conflicting mutable borrow against that would panic instead of reporting the
+        // typed error this wrapper exists to provide.
+        let val = self
+            .slot
+            .try_borrow_mut()
+            .map_err(|_| ReentrantCallError::new(instance_ptr))?
+            .take()
+            .ok_or_else(|| ReentrantCallError::new(instance_ptr))?;
+
+        Ok(ReentrantGuard {
+            slot: &self.slot,
+            val: Some(val),
+        })
+    }
+}
+
+impl<T> UserData for ReentrantCell<T>
+where
+    T: 'static,
+{
+    type Target = T;
+
+    fn new(val: Self::Target) -> Self {
+        ReentrantCell {
+            slot: Rc::new(RefCell::new(Some(val))),
+        }
+    }
+
+    fn map<F, U>(&self, op: F) -> Result<U, ReentrantCallError>
+    where
+        F: FnOnce(&Self::Target) -> U,
+    {
+        // A shared borrow of the slot, unlike `borrow_mut`, does not take the value out: it
+        // only looks at it. This is what lets concurrent immutable re-entrant calls succeed
+        // together, as `UserData::map` promises -- only a live mutable borrow (which empties
+        // the slot) turns this into `Err`.
+        let instance_ptr = Rc::as_ptr(&self.slot) as usize;
+        let slot = self.slot.borrow();
+        match slot.as_ref() {
+            Some(val) => Ok(op(val)),
+            None => Err(ReentrantCallError::new(instance_ptr)),
+        }
+    }
+
+    fn map_mut<F, U>(&self, op: F) -> Result<U, ReentrantCallError>
+    where
+        F: FnOnce(&mut Self::Target) -> U,
+    {
+        let mut guard = self.borrow_mut()?;
+        Ok(op(&mut guard))
+    }
+}
+
+/// Guard returned by [`ReentrantCell::borrow_mut`]. Puts the value back into its cell when
+/// dropped, unless it is currently released by [`with_released`](Self::with_released).
+pub struct ReentrantGuard<'a, T> {
+    slot: &'a RefCell<Option<T>>,
+    // Always `Some` except transiently inside `with_released`, where it is logically absent
+    // from the guard's point of view (the value itself lives in `slot` for the span of `f`).
+    val: Option<T>,
+}
+
+impl<'a, T> ReentrantGuard<'a, T> {
+    /// Puts the value back into the cell, runs `f` -- which may re-enter
+    /// [`ReentrantCell::borrow_mut`] on the same instance -- and then takes the value back out
+    /// again.
+    ///
+    /// During `f`, a nested `borrow_mut` on this instance sees the value as present and may
+    /// borrow it in turn; this guard sees it as absent until `f` returns.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the value was not put back by the time `f` returns, e.g. because `f` itself
+    /// left a nested `ReentrantGuard` for this instance unreleased.
+    pub fn with_released<R>(&mut self, f: impl FnOnce() -> R) -> R {
+        let val = self.val.take().expect("value already released");
+        *self.slot.borrow_mut() = Some(val);
+
+        let result = f();
+
+        self.val = Some(
+            self.slot
+                .borrow_mut()
+                .take()
+                .unwrap_or_else(|| panic!("value for instance was not returned after release")),
+        );
+
+        result
+    }
+}
+
+impl<'a, T> Deref for ReentrantGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.val
+            .as_ref()
+            .expect("value is released for a nested call")
+    }
+}
+
+impl<'a, T> DerefMut for ReentrantGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.val
+            .as_mut()
+            .expect("value is released for a nested call")
+    }
+}
+
+impl<'a, T> Drop for ReentrantGuard<'a, T> {
+    fn drop(&mut self) {
+        if let Some(val) = self.val.take() {
+            *self.slot.borrow_mut() = Some(val);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::panic::{self, AssertUnwindSafe};
+
+    #[test]
+    fn local_cell_data_nested_map_mut_errs_instead_of_panicking() {
+        let data = LocalCellData::new(0i32);
+
+        let result = data.map_mut(|outer| {
+            *outer += 1;
+            // Re-entering the same instance for another mutable borrow must fail cleanly,
+            // not panic or abort.
+            data.map_mut(|inner| *inner += 1)
+        });
+
+        assert!(result.unwrap().is_err());
+    }
+
+    #[test]
+    fn reentrancy_guard_releases_on_panic() {
+        let data = LocalCellData::new(0i32);
+
+        let panicked = panic::catch_unwind(AssertUnwindSafe(|| {
+            let _ = data.map_mut(|_| panic!("boom"));
+        }))
+        .is_err();
+        assert!(panicked);
+
+        // If the thread-local guard had been left set by the unwind, this would incorrectly
+        // return `Err` even though nothing is borrowing `data` anymore.
+        assert!(data.map_mut(|val| *val += 1).is_ok());
+    }
+
+    #[test]
+    fn unrelated_instances_do_not_false_flag_each_other() {
+        let a = LocalCellData::new(1i32);
+        let b = LocalCellData::new(2i32);
+
+        let result = a.map_mut(|a_val| {
+            *a_val += 10;
+            b.map_mut(|b_val| {
+                *b_val += 20;
+                (*a_val, *b_val)
+            })
+        });
+
+        assert_eq!(result.unwrap().unwrap(), (11, 22));
+    }
+
+    #[test]
+    fn mutex_data_nested_map_errs_instead_of_deadlocking() {
+        let data = MutexData::new(0i32);
+
+        // `MutexData` has no shared-lock mode, so even a nested immutable `map` must be
+        // rejected rather than contending for the already-held lock.
+        let result = data.map(|_| data.map(|val| *val));
+
+        assert!(result.unwrap().is_err());
+    }
+
+    #[test]
+    fn mutex_data_nested_map_mut_errs_instead_of_deadlocking() {
+        let data = MutexData::new(0i32);
+
+        let result = data.map_mut(|outer| {
+            *outer += 1;
+            data.map_mut(|inner| *inner += 1)
+        });
+
+        assert!(result.unwrap().is_err());
+    }
+
+    #[test]
+    fn reentrant_cell_with_released_allows_recursive_borrow_mut() {
+        let data = ReentrantCell::new(0i32);
+
+        let mut outer = data.borrow_mut().unwrap();
+        *outer += 1;
+
+        outer.with_released(|| {
+            let mut inner = data.borrow_mut().unwrap();
+            *inner += 1;
+        });
+
+        *outer += 1;
+        drop(outer);
+
+        assert_eq!(*data.borrow_mut().unwrap(), 3);
+    }
+
+    #[test]
+    fn reentrant_cell_borrow_mut_without_release_errs() {
+        let data = ReentrantCell::new(0i32);
+
+        let _outer = data.borrow_mut().unwrap();
+        assert!(data.borrow_mut().is_err());
+    }
+
+    #[test]
+    fn reentrant_cell_map_allows_concurrent_immutable_reentrant_access() {
+        let data = ReentrantCell::new(42i32);
+
+        let result = data.map(|outer| data.map(|inner| *outer + *inner));
+
+        assert_eq!(result.unwrap().unwrap(), 84);
+    }
+
+    #[test]
+    fn reentrant_cell_mutable_reentry_from_map_errs_instead_of_panicking() {
+        let data = ReentrantCell::new(0i32);
+
+        // `map` keeps a shared borrow of the slot alive for the whole closure, so a nested
+        // `map_mut` must report the typed error rather than panicking on a conflicting borrow.
+        let result = data.map(|_| data.map_mut(|v| *v += 1));
+
+        assert!(result.unwrap().is_err());
+    }
+}